@@ -1,169 +1,1013 @@
-use hashbrown::HashMap;
 use tracing_core::span::Id;
 use std::{
-    mem,
-    thread,
-    sync::atomic::{AtomicUsize, Ordering},
-    cell::{RefCell, Cell},
+    cmp::Reverse,
+    collections::BinaryHeap,
+    sync::{atomic::{AtomicUsize, Ordering}, Arc},
+    cell::RefCell,
+};
+use hashbrown::HashMap;
+use parking_lot::{
+    Mutex, ReentrantMutex, ReentrantMutexGuard, MappedReentrantMutexGuard, RwLock,
 };
-use parking_lot::{ReentrantMutex, ReentrantMutexGuard, MappedReentrantMutexGuard};
-use crossbeam_utils::sync::{ShardedLock, ShardedLockReadGuard};
 use owning_ref::OwningHandle;
 
 pub struct Registry<T> {
-    shards: ShardedLock<Shards<T>>,
+    shards: RwLock<Shards<T>>,
 }
 
-#[derive(Copy, Clone, Hash, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
 struct Thread {
     id: usize,
 }
 
-struct Shards<T>(HashMap<Thread, Shard<T>>);
+struct Shards<T>(HashMap<Thread, Arc<Shard<T>>>);
 
 struct Shard<T> {
-    spans: ReentrantMutex<RefCell<HashMap<Id, T>>>,
+    storage: ReentrantMutex<RefCell<ShardStorage<T>>>,
 }
 
+/// `OwningHandle`'s owner type needs to implement `owning_ref::StableAddress`
+/// --- a guarantee that its `Deref` target doesn't move even if the owner
+/// itself does. `parking_lot`'s lock guards only provide that under an
+/// opt-in Cargo feature, so borrowing straight through a
+/// `RwLockReadGuard<'a, Shards<T>>` here (as this used to) silently depends
+/// on that feature being enabled wherever this crate is built. `Arc`
+/// implements `StableAddress` unconditionally --- its target lives on the
+/// heap at a fixed address regardless of how many handles to it move
+/// around --- so `Ref` instead clones the shard's `Arc` out from under the
+/// registry's read lock (see `Registry::ref_at`) and owns that.
 pub struct Ref<'a, T> {
     inner: OwningHandle<
-        ShardedLockReadGuard<'a, Shard<T>>,
-        MappedReentrantMutexGuard<'a, &'a mut T>
+        Arc<Shard<T>>,
+        MappedReentrantMutexGuard<'a, T>
     >,
 }
 
+impl<'a, T> std::ops::Deref for Ref<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
 
-#[derive(Clone, Debug)]
+/// The contents of a single slot.
+///
+/// A span lives `Present` in exactly one slot at a time. When it's looked up
+/// or entered from a thread other than the one whose shard currently holds
+/// it, the registry *steals* it: the span is moved into the looking-up
+/// thread's own shard, and the slot it was stolen from is left with a
+/// `Stolen` marker pointing at its new location.
+///
+/// This means a `Stolen(key)` slot must always be resolvable by looking at
+/// `key` --- though that slot might itself only hold another forwarding
+/// marker, if the span has since been stolen again. A lookup has to be
+/// prepared to follow a chain of these markers, not just assume a single hop
+/// lands on the real value.
+///
+/// A `Stolen` slot isn't freed when it's created --- the span it used to
+/// hold might still be reachable through it. It's only reclaimed once the
+/// span has actually been dropped, at which point `PageSlot::forwarded_from`
+/// lets the chain be unwound one hop at a time; see `free_stolen`.
+#[derive(Debug)]
 enum Slot<T> {
     Present(T),
-    Stolen(Thread),
+    Stolen(SlotKey),
+}
+
+/// Outcome of trying to touch a slot's refcount at a key that was resolved
+/// to `Present` at some point in the past, but that a concurrent steal may
+/// have since turned into a `Stolen` marker --- without bumping the slot's
+/// generation, since the span it pointed at is still reachable, just not
+/// from here anymore. A stale generation means the span is genuinely gone;
+/// a `Stolen` marker at a matching generation means it just moved, and the
+/// caller should follow `next` and retry rather than treating it as a miss.
+enum Touch<I> {
+    /// The slot was still `Present`; the refcount change went through.
+    Done(I),
+    /// The slot now forwards to `next`; retry the operation there.
+    Stolen(SlotKey),
+}
+
+/// What `ShardStorage::drop_ref` did when it reached a `Present` slot.
+enum DropRef {
+    /// The refcount was decremented but is still positive.
+    NotReleased,
+    /// The refcount hit zero and the slot was freed; carries the donor slot
+    /// (if any) whose `Stolen` tombstone should now be reclaimed in turn.
+    Released(Option<SlotKey>),
 }
 
-fn handle_poison<T>(result: Result<T, ()>) -> Option<T> {
-    if thread::panicking() {
-        result.ok()
+/// Outcome of `Shard::with_present`, which can't reuse `Touch` as-is because
+/// a `Stolen` slot means `f` was never called and has to be handed back so
+/// the caller can retry it, rather than just a key to retry at.
+enum Access<F, I> {
+    /// The slot was `Present`; `f` ran and produced `I`.
+    Found(I),
+    /// The slot has since been stolen; `f` was never called, so it's handed
+    /// back unused for the caller to retry once the span is found again.
+    Stolen(F),
+}
+
+/// Number of slots per page. Kept a power of two so the offset within a page
+/// is just the low bits of a slot's flat index.
+const PAGE_SIZE: usize = 256;
+
+const OFFSET_BITS: u32 = 8; // log2(PAGE_SIZE)
+const GENERATION_BITS: u32 = 16;
+const PAGE_BITS: u32 = 24;
+const SHARD_BITS: u32 = 64 - OFFSET_BITS - GENERATION_BITS - PAGE_BITS;
+
+const GENERATION_SHIFT: u32 = 0;
+const OFFSET_SHIFT: u32 = GENERATION_SHIFT + GENERATION_BITS;
+const PAGE_SHIFT: u32 = OFFSET_SHIFT + OFFSET_BITS;
+const SHARD_SHIFT: u32 = PAGE_SHIFT + PAGE_BITS;
+
+const GENERATION_MASK: u64 = (1 << GENERATION_BITS) - 1;
+const OFFSET_MASK: u64 = (1 << OFFSET_BITS) - 1;
+const PAGE_MASK: u64 = (1 << PAGE_BITS) - 1;
+const SHARD_MASK: u64 = (1 << SHARD_BITS) - 1;
+
+/// A decoded span `Id`: which shard it lives on, which page and offset
+/// within that shard's slab, and the generation the slot was in when the
+/// `Id` was handed out.
+///
+/// Packed into a `u64` as `[shard:16][page:24][offset:8][generation:16]`, so
+/// a lookup can index straight into the right page and slot instead of
+/// hashing. The generation is what makes this safe: a slot's generation is
+/// bumped every time it changes hands, so a stale `Id` whose generation no
+/// longer matches the slot it points at is known to refer to a span that's
+/// gone, even if the slot has since been reused for something else (the
+/// classic ABA problem a bare index would be vulnerable to).
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+struct SlotKey {
+    shard: usize,
+    page: usize,
+    offset: usize,
+    generation: usize,
+}
+
+impl SlotKey {
+    fn to_id(self) -> Id {
+        let bits = ((self.shard as u64 & SHARD_MASK) << SHARD_SHIFT)
+            | ((self.page as u64 & PAGE_MASK) << PAGE_SHIFT)
+            | ((self.offset as u64 & OFFSET_MASK) << OFFSET_SHIFT)
+            | ((self.generation as u64 & GENERATION_MASK) << GENERATION_SHIFT);
+        Id::from_u64(bits)
+    }
+
+    fn from_id(id: &Id) -> Self {
+        let bits = id.into_u64();
+        Self {
+            shard: ((bits >> SHARD_SHIFT) & SHARD_MASK) as usize,
+            page: ((bits >> PAGE_SHIFT) & PAGE_MASK) as usize,
+            offset: ((bits >> OFFSET_SHIFT) & OFFSET_MASK) as usize,
+            generation: ((bits >> GENERATION_SHIFT) & GENERATION_MASK) as usize,
+        }
+    }
+}
+
+/// Bumps a slot's generation, skipping zero --- a generation of zero would
+/// let an all-zero `SlotKey` pack down to the `Id` value `0`, which
+/// `tracing_core::Id` treats as invalid.
+fn next_generation(current: usize) -> usize {
+    let next = (current.wrapping_add(1)) & GENERATION_MASK as usize;
+    if next == 0 {
+        1
     } else {
-        Some(result.expect("registry poisoned"))
+        next
     }
 }
 
+struct PageSlot<T> {
+    generation: usize,
+    /// How many outstanding handles reference this slot's span. Only
+    /// meaningful while `value` is `Some(Slot::Present(_))` --- a `Stolen`
+    /// marker doesn't carry its own refcount, since the count belongs to
+    /// wherever the span is actually `Present`.
+    refs: usize,
+    value: Option<Slot<T>>,
+    /// If this slot's span arrived here via a cross-thread steal, the key
+    /// of the donor slot that now holds a `Stolen` marker pointing here.
+    /// `None` for a span that was `insert`ed directly and has never been
+    /// stolen (yet).
+    ///
+    /// This is what lets a span's tombstones be reclaimed: once this slot's
+    /// span is itself fully removed, `free_stolen` follows `forwarded_from`
+    /// back to the donor, frees its tombstone too, and keeps walking --- so
+    /// a span stolen N times doesn't leave N slots permanently allocated.
+    forwarded_from: Option<SlotKey>,
+}
+
+impl<T> PageSlot<T> {
+    fn new() -> Self {
+        Self {
+            generation: 0,
+            refs: 0,
+            value: None,
+            forwarded_from: None,
+        }
+    }
+}
+
+/// A type whose storage can be reset in place, handed back to its slot's
+/// free-list with its allocation intact rather than dropped.
+///
+/// Implemented by hand for types that actually own a reusable allocation
+/// (e.g. calling `Vec::clear`/`String::clear`, which empty the buffer
+/// without releasing its capacity) rather than derived, since a derive
+/// based on `Default` would throw the allocation away and defeat the
+/// point.
+pub trait Clear {
+    fn clear(&mut self);
+}
+
+/// A shard's backing storage: a growable list of fixed-size pages of slots,
+/// plus a free-list stack of slot indices available for reuse.
+///
+/// This replaces the `HashMap<Id, _>` the shard used to be keyed by: instead
+/// of hashing an `Id` to find its span, the `Id` *is* the address (shard,
+/// page, offset) of the slot that holds it, so a lookup indexes straight in.
+struct ShardStorage<T> {
+    pages: Vec<Vec<PageSlot<T>>>,
+    free: Vec<usize>,
+}
+
+impl<T> ShardStorage<T> {
+    fn new() -> Self {
+        Self {
+            pages: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    /// Pops a free slot's flat index, growing the slab by one page if the
+    /// free-list is empty.
+    fn alloc(&mut self) -> usize {
+        if let Some(index) = self.free.pop() {
+            return index;
+        }
+
+        let page = self.pages.len();
+        self.pages.push((0..PAGE_SIZE).map(|_| PageSlot::new()).collect());
+
+        let base = page * PAGE_SIZE;
+        // Queue up the rest of the new page so the next few allocations
+        // don't need to grow the slab again right away.
+        self.free.extend((1..PAGE_SIZE).rev().map(|offset| base + offset));
+        base
+    }
+
+    fn slot(&self, page: usize, offset: usize) -> Option<&PageSlot<T>> {
+        self.pages.get(page)?.get(offset)
+    }
+
+    fn slot_mut(&mut self, page: usize, offset: usize) -> Option<&mut PageSlot<T>> {
+        self.pages.get_mut(page)?.get_mut(offset)
+    }
+
+    fn insert(&mut self, shard: usize, value: Slot<T>, refs: usize) -> SlotKey {
+        let index = self.alloc();
+        let (page, offset) = (index / PAGE_SIZE, index % PAGE_SIZE);
+        let slot = &mut self.pages[page][offset];
+        slot.generation = next_generation(slot.generation);
+        slot.refs = refs;
+        slot.forwarded_from = None;
+        slot.value = Some(value);
+        SlotKey { shard, page, offset, generation: slot.generation }
+    }
+
+    /// Like `insert`, but records that `value` arrived in this slot via a
+    /// cross-thread steal from `from`'s slot, which now holds a `Stolen`
+    /// marker pointing here. Used by `Registry::steal_locked` so the
+    /// tombstone left behind at `from` can eventually be reclaimed by
+    /// `free_stolen` once this slot's span is fully dropped.
+    fn insert_relocated(&mut self, shard: usize, value: T, refs: usize, from: SlotKey) -> SlotKey {
+        let key = self.insert(shard, Slot::Present(value), refs);
+        if let Some(slot) = self.slot_mut(key.page, key.offset) {
+            slot.forwarded_from = Some(from);
+        }
+        key
+    }
+
+    /// Like `insert`, but reuses whatever value a previous occupant of the
+    /// popped slot left behind --- already `clear`ed by `drop_ref` when its
+    /// refcount hit zero --- instead of allocating a new `T`, handing it to
+    /// `init` to refill in place. Brand-new slots (with no prior occupant)
+    /// fall back to `T::default()`.
+    fn insert_with(&mut self, shard: usize, init: impl FnOnce(&mut T)) -> SlotKey
+    where
+        T: Default,
+    {
+        let index = self.alloc();
+        let (page, offset) = (index / PAGE_SIZE, index % PAGE_SIZE);
+        let slot = &mut self.pages[page][offset];
+        slot.generation = next_generation(slot.generation);
+        slot.refs = 1;
+        slot.forwarded_from = None;
+
+        let mut value = match slot.value.take() {
+            Some(Slot::Present(existing)) => existing,
+            _ => T::default(),
+        };
+        init(&mut value);
+        slot.value = Some(Slot::Present(value));
+        SlotKey { shard, page, offset, generation: slot.generation }
+    }
+
+    /// Removes the value at `key` outright, bumping its generation and
+    /// returning the slot to the free-list, along with the key of whatever
+    /// donor slot forwarded this span here via a steal (if any), so the
+    /// caller can keep unwinding that chain with `free_stolen`. Returns
+    /// `None` (without freeing anything) if `key`'s generation is stale.
+    ///
+    /// Only ever called (via `Shard::remove`) from the test-only
+    /// `Registry::remove_for_test`, which bypasses the refcount entirely;
+    /// gated behind `#[cfg(test)]` so a non-test build doesn't carry dead
+    /// code.
+    #[cfg(test)]
+    fn remove(&mut self, key: &SlotKey) -> Option<(Slot<T>, Option<SlotKey>)> {
+        let slot = self.slot_mut(key.page, key.offset)?;
+        if slot.generation != key.generation {
+            return None;
+        }
+        let value = slot.value.take();
+        let forwarded_from = slot.forwarded_from.take();
+        slot.generation = next_generation(slot.generation);
+        self.free.push(key.page * PAGE_SIZE + key.offset);
+        value.map(|value| (value, forwarded_from))
+    }
+
+    /// Decrements `key`'s refcount. If it drops to zero, clears the stored
+    /// value in place (so `insert_with` can reuse its allocation later) and
+    /// returns the slot to the free-list. Returns `None` if `key`'s
+    /// generation is stale (the span is genuinely gone), or
+    /// `Some(Touch::Stolen(next))` if a steal moved the span out from under
+    /// `key` since it was resolved --- without bumping the generation, so
+    /// the caller has to check the slot's contents, not just its generation,
+    /// to notice --- in which case the caller should retry at `next` rather
+    /// than treating this as a miss.
+    fn drop_ref(&mut self, key: &SlotKey) -> Option<Touch<DropRef>>
+    where
+        T: Clear,
+    {
+        let slot = self.slot_mut(key.page, key.offset)?;
+        if slot.generation != key.generation {
+            return None;
+        }
+        match slot.value.as_ref()? {
+            Slot::Stolen(next) => return Some(Touch::Stolen(*next)),
+            Slot::Present(_) => {}
+        }
+        slot.refs = slot.refs.saturating_sub(1);
+        if slot.refs > 0 {
+            return Some(Touch::Done(DropRef::NotReleased));
+        }
+        if let Some(Slot::Present(value)) = &mut slot.value {
+            value.clear();
+        }
+        let forwarded_from = slot.forwarded_from.take();
+        slot.generation = next_generation(slot.generation);
+        self.free.push(key.page * PAGE_SIZE + key.offset);
+        Some(Touch::Done(DropRef::Released(forwarded_from)))
+    }
+
+    /// Frees a `Stolen` tombstone at `key` --- called once whatever it
+    /// forwarded to has itself been fully removed --- returning the key of
+    /// whichever slot forwarded *to* this one, if any, so the whole chain
+    /// of past steals can be unwound one hop at a time instead of leaving
+    /// every earlier hop permanently allocated. Returns `None` (without
+    /// freeing anything) if `key` is stale or no longer holds a `Stolen`
+    /// marker.
+    fn free_stolen(&mut self, key: &SlotKey) -> Option<SlotKey> {
+        let slot = self.slot_mut(key.page, key.offset)?;
+        if slot.generation != key.generation {
+            return None;
+        }
+        match &slot.value {
+            Some(Slot::Stolen(_)) => {}
+            _ => return None,
+        }
+        slot.value = None;
+        let forwarded_from = slot.forwarded_from.take();
+        slot.generation = next_generation(slot.generation);
+        self.free.push(key.page * PAGE_SIZE + key.offset);
+        forwarded_from
+    }
+}
+
+/// No span exists for the `Id` a `try_with_span` call was given --- it may
+/// never have existed on this registry, or may already have been fully
+/// dropped.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SpanNotFound;
+
 impl<T> Registry<T> {
-    fn with_shard<I>(&self, mut f: impl FnOnce(&mut HashMap<Id, T>) -> I) -> Result<I, ()> {
-        // fast path --- the shard already exists
+    /// Picks the current thread's shard, creating it on first visit, locks
+    /// it, and runs `f` against its storage --- the one place a caller goes
+    /// from "current thread" to "a locked shard ready to use", so
+    /// `with_span`/`insert`/etc. don't each have to know how shard
+    /// selection works.
+    ///
+    /// The common case (this thread has visited before) only ever takes a
+    /// read lock and does a single hash lookup. Only a thread's very first
+    /// call takes the write lock, and even then uses the hashbrown `entry`
+    /// API to do the "is it there? insert it if not" check in one lookup
+    /// rather than inserting and then looking the new entry back up again.
+    fn lock_shard_for<I>(&self, thread: Thread, f: impl FnOnce(&mut ShardStorage<T>) -> I) -> I {
+        if let Some(shard) = self.shards.read().0.get(&thread) {
+            return shard.with_storage(f);
+        }
+
+        self.shards.write().0.entry(thread).or_insert_with(|| Arc::new(Shard::new())).with_storage(f)
+    }
+
+    fn with_shard<I>(&self, f: impl FnOnce(&mut ShardStorage<T>) -> I) -> I {
+        self.lock_shard_for(Thread::current(), f)
+    }
+
+    /// Follows `Slot::Stolen` markers starting from `key` until landing on a
+    /// slot that's either `Present` or doesn't resolve at all, without
+    /// moving anything.
+    ///
+    /// A span can be stolen again every time it's looked up from a new
+    /// thread, so the chain length is bounded by how many times *this
+    /// span* has ever changed hands, not by the number of live shards ---
+    /// a span passed back and forth between two threads more than
+    /// `shard_count` times would otherwise make this give up on a span
+    /// that's still very much alive. Instead, guard only against a chain
+    /// that's somehow cyclic (which would otherwise spin forever) by
+    /// tracking the keys visited so far.
+    fn resolve(&self, mut key: SlotKey) -> Option<SlotKey> {
+        let shards = self.shards.read();
+        let mut visited = Vec::new();
+
+        loop {
+            if visited.contains(&key) {
+                return None;
+            }
+            visited.push(key);
+
+            let shard = shards.0.get(&Thread { id: key.shard })?;
+            match shard.peek(&key)? {
+                Slot::Present(()) => return Some(key),
+                Slot::Stolen(next) => key = next,
+            }
+        }
+    }
+
+    /// Makes sure the span named by `id` lives in the current thread's own
+    /// shard, stealing it from wherever it currently lives if it doesn't,
+    /// and returns the (now-local) key for it.
+    ///
+    /// The donor shard's lock is held for the *entire* steal, from taking
+    /// the span out of its slot to leaving the `Stolen` marker behind, so a
+    /// concurrent `resolve`/`peek` on that exact key can never observe the
+    /// in-between state where the slot is neither `Present` nor yet marked
+    /// `Stolen` --- it either still sees the old `Present` value or already
+    /// sees the new `Stolen` marker. Both shards' locks are taken up front,
+    /// in a fixed order (by thread id), so two threads stealing from each
+    /// other at the same time can't deadlock.
+    ///
+    /// `resolve` only peeks at slots under the registry's read lock, without
+    /// holding any shard's lock, so the key it hands back can already be
+    /// stale by the time `steal_locked` actually takes the donor's lock ---
+    /// another thread can race in and steal the same span first. That race
+    /// leaves a `Stolen` marker behind without bumping the generation (same
+    /// as the `clone_ref`/`drop_ref` race `b9d3f3a` fixed), so `steal_locked`
+    /// hands the forward pointer back as `Err(next)` instead of `None`, and
+    /// this loops to keep following it rather than treating a span that
+    /// merely moved again as gone.
+    fn localize(&self, id: &Id) -> Option<SlotKey> {
         let thread = Thread::current();
-        let mut f = Some(f);
+        let mut key = self.resolve(SlotKey::from_id(id))?;
+
+        loop {
+            if key.shard == thread.id {
+                return Some(key);
+            }
 
-        if let Some(r) = self.shards.read().map_err(|_|())?
-            .with_shard(&thread, &mut f)
-        {
-            return Ok(r)
+            // Make sure our own shard exists before we need to address it below.
+            self.with_shard(|_| {});
+
+            let shards = self.shards.read();
+            let donor = shards.0.get(&Thread { id: key.shard })?;
+            let mine = shards.0.get(&thread)?;
+
+            let outcome = if key.shard < thread.id {
+                let donor = donor.lock();
+                let mine = mine.lock();
+                Self::steal_locked(&donor, &mine, key, thread.id)
+            } else {
+                let mine = mine.lock();
+                let donor = donor.lock();
+                Self::steal_locked(&donor, &mine, key, thread.id)
+            };
+
+            match outcome? {
+                Ok(new_key) => return Some(new_key),
+                Err(next) => key = next,
+            }
         }
-        // slow path --- need to insert a shard.
-        self.shards.write().map_err(|_|())?
-            .new_shard_for(thread.clone())
-            .with_shard(&thread, &mut f).ok_or(())
     }
 
+    /// Moves the span at `key` out of `donor`'s storage and into a fresh
+    /// slot in `mine`'s, leaving a `Stolen` marker behind at `key`. Both
+    /// guards must already be locked and are held for the whole operation
+    /// (see `localize`). Returns `None`, leaving both shards untouched, if
+    /// `key`'s generation is stale (the span is genuinely gone). Returns
+    /// `Some(Err(next))` if another thread already stole this exact span out
+    /// from under us between `resolve` and here, without bumping the
+    /// generation --- `localize` should retry at `next` rather than give up.
+    fn steal_locked(
+        donor: &ReentrantMutexGuard<'_, RefCell<ShardStorage<T>>>,
+        mine: &ReentrantMutexGuard<'_, RefCell<ShardStorage<T>>>,
+        key: SlotKey,
+        my_shard: usize,
+    ) -> Option<Result<SlotKey, SlotKey>> {
+        let (span, refs) = {
+            let mut donor = donor.borrow_mut();
+            let slot = donor.slot_mut(key.page, key.offset)?;
+            if slot.generation != key.generation {
+                return None;
+            }
+            match slot.value.take()? {
+                Slot::Present(span) => (span, slot.refs),
+                stolen @ Slot::Stolen(next) => {
+                    slot.value = Some(stolen);
+                    return Some(Err(next));
+                }
+            }
+        };
+
+        let new_key = mine.borrow_mut().insert_relocated(my_shard, span, refs, key);
+
+        let mut donor = donor.borrow_mut();
+        if let Some(slot) = donor.slot_mut(key.page, key.offset) {
+            slot.value = Some(Slot::Stolen(new_key));
+        }
+
+        Some(Ok(new_key))
+    }
+
+    /// `localize` guarantees `id` lives in the caller's own shard at the
+    /// key it returns, but that guarantee is only good for the instant it's
+    /// made: another thread can steal the span away again before this
+    /// function gets as far as actually reading it. When that happens,
+    /// `ref_at` comes back empty even though the span is still very much
+    /// alive elsewhere, so we re-`localize` and try again rather than
+    /// reporting a miss --- which also naturally tells a genuine miss (the
+    /// span fully dropped, `localize` itself returning `None`) apart from
+    /// the race, since only the latter has anywhere left to re-resolve to.
     pub fn get_span<'a>(&'a self, id: &Id) -> Option<Ref<'a, T>> {
-        unimplemented!()
+        let mut key = self.localize(id)?;
+        loop {
+            if let Some(r) = self.ref_at(key) {
+                return Some(r);
+            }
+            key = self.localize(id)?;
+        }
+    }
+
+    /// Builds a `Ref` directly at `key`, without stealing it into the
+    /// current thread's shard first, and without retrying if a concurrent
+    /// steal beats it to the slot. Used by `get_span` (which handles that
+    /// race itself by re-`localize`ing) and by `iter` (which reads spans in
+    /// place, wherever they currently live, since relocating them as a side
+    /// effect of a diagnostic walk would be surprising, and simply treats a
+    /// span that moved mid-walk as missed rather than chasing it).
+    ///
+    /// Clones the shard's `Arc` out from under the registry's read lock
+    /// rather than keeping that lock held for the `Ref`'s whole lifetime:
+    /// `Arc` is always a stable address for `owning_ref`'s `OwningHandle` to
+    /// borrow through, whereas doing the same directly with
+    /// `parking_lot::RwLockReadGuard` would require its optional
+    /// `owning_ref` feature to be enabled wherever this crate is built.
+    fn ref_at<'a>(&'a self, key: SlotKey) -> Option<Ref<'a, T>> {
+        let shard = self.shards.read().0.get(&Thread { id: key.shard })?.clone();
+        let inner = OwningHandle::try_new(shard, |shard| {
+            // Safety: `OwningHandle` keeps the `Arc` we just moved in alive
+            // for as long as the `MappedReentrantMutexGuard` we're building
+            // from it, so this borrow is sound.
+            let shard = unsafe { &*shard };
+            shard.present(&key).ok_or(())
+        }).ok()?;
+
+        Some(Ref { inner })
     }
 
     pub fn with_span<I>(&self, id: &Id, f: impl FnOnce(&mut T) -> I) -> Option<I> {
-        let mut f = Some(f);
-        let res = self.with_shard(|shard| {
-            shard.get_mut(id).and_then(Slot::get_mut).map(|span| {
-                let mut f = f.take().expect("called twice!");
-                f(span)
-            })
-        });
-        handle_poison(res)?
+        let mut key = self.localize(id)?;
+        let mut f = f;
+        loop {
+            let outcome = {
+                let shards = self.shards.read();
+                let shard = shards.0.get(&Thread { id: key.shard })?;
+                shard.with_present(&key, f)?
+            };
+            match outcome {
+                Access::Found(i) => return Some(i),
+                // Same race as `get_span`: the span was stolen again between
+                // `localize` resolving `key` and this read taking the shard
+                // lock. Re-`localize` to pull it back local and retry with
+                // the closure `with_present` handed back to us, instead of
+                // reporting a miss or silently dropping it unused.
+                Access::Stolen(returned_f) => {
+                    f = returned_f;
+                    key = self.localize(id)?;
+                }
+            }
+        }
+    }
 
-        // TODO: steal
+    /// Like `with_span`, but reports a missing span as `Err(SpanNotFound)`
+    /// rather than silently returning `None`, for callers that want to handle a missing
+    /// span explicitly instead of treating it the same as "found but the
+    /// closure produced nothing".
+    pub fn try_with_span<I>(&self, id: &Id, f: impl FnOnce(&mut T) -> I) -> Result<I, SpanNotFound> {
+        self.with_span(id, f).ok_or(SpanNotFound)
     }
 
-    pub fn insert(&self, id: Id, span: T) -> &Self {
-        let ok = self.with_shard(move |shard| {
-            let _ = shard.insert(id, span);
-        });
-        if !thread::panicking() {
-            ok.expect("poisoned");
+    pub fn insert(&self, span: T) -> Id {
+        let thread = Thread::current();
+        self.with_shard(|storage| storage.insert(thread.id, Slot::Present(span), 1)).to_id()
+    }
+
+    /// Like `insert`, but reuses a previous occupant's cleared allocation
+    /// when one is available instead of always constructing a fresh `T`,
+    /// handing `init` a chance to refill it in place. Useful for spans'
+    /// per-call extension data, which tends to churn `String`/`Vec` fields
+    /// on every span create/close without needing to reallocate each time.
+    pub fn insert_with(&self, init: impl FnOnce(&mut T)) -> Id
+    where
+        T: Default,
+    {
+        let thread = Thread::current();
+        self.with_shard(move |storage| storage.insert_with(thread.id, init)).to_id()
+    }
+
+    /// Increments the span's refcount, returning the `Id` of wherever the
+    /// span currently lives, or `None` if `id` no longer resolves to a live
+    /// span.
+    ///
+    /// `resolve` can come back with a key that a concurrent steal moves on
+    /// from again before `clone_ref` gets to touch it --- the donor slot
+    /// it leaves behind isn't generation-bumped, only turned into a
+    /// `Stolen` marker, so `clone_ref` has to check the slot's contents,
+    /// not just its generation, to notice and follow the chain instead of
+    /// clobbering the tombstone's meaningless refcount.
+    pub fn clone_span(&self, id: &Id) -> Option<Id> {
+        let mut key = self.resolve(SlotKey::from_id(id))?;
+        loop {
+            let shards = self.shards.read();
+            let shard = shards.0.get(&Thread { id: key.shard })?;
+            match shard.clone_ref(&key)? {
+                Touch::Done(()) => return Some(key.to_id()),
+                Touch::Stolen(next) => key = next,
+            }
+        }
+    }
+
+    /// Decrements the span's refcount. Once it reaches zero the slot's
+    /// value is cleared in place (see `Clear`) and handed back to its
+    /// shard's free-list for reuse. Returns `true` if this call was the one
+    /// that released it.
+    ///
+    /// Same steal race as `clone_span`: a `resolve`d key can be forwarded
+    /// again by the time `drop_ref` runs, so it's retried at `next` rather
+    /// than treated as already released.
+    pub fn drop_span(&self, id: &Id) -> bool
+    where
+        T: Clear,
+    {
+        let mut key = match self.resolve(SlotKey::from_id(id)) {
+            Some(key) => key,
+            None => return false,
+        };
+
+        loop {
+            let outcome = {
+                let shards = self.shards.read();
+                match shards.0.get(&Thread { id: key.shard }) {
+                    Some(shard) => shard.drop_ref(&key),
+                    None => return false,
+                }
+            };
+
+            match outcome {
+                Some(Touch::Done(DropRef::NotReleased)) => return false,
+                Some(Touch::Done(DropRef::Released(forwarded_from))) => {
+                    self.reclaim_chain(forwarded_from);
+                    return true;
+                }
+                Some(Touch::Stolen(next)) => key = next,
+                None => return false,
+            }
         }
+    }
 
-        self
+    /// Once a span's last handle has been dropped, walks back through
+    /// however many times it was stolen over its life, freeing each donor
+    /// slot's `Stolen` tombstone in turn. Without this, every cross-thread
+    /// steal a span ever underwent would leave a slot permanently
+    /// allocated, long after the span itself is gone.
+    fn reclaim_chain(&self, mut forwarded_from: Option<SlotKey>) {
+        while let Some(key) = forwarded_from {
+            let shards = self.shards.read();
+            forwarded_from = match shards.0.get(&Thread { id: key.shard }) {
+                Some(shard) => shard.free_stolen(&key),
+                None => None,
+            };
+        }
     }
 
     pub fn new() -> Self {
         Self {
-            shards: ShardedLock::new(Shards(HashMap::new()))
+            shards: RwLock::new(Shards(HashMap::new()))
         }
     }
-}
 
-impl<T> Shards<T> {
-    fn with_shard<I>(
-        &self,
-        thread: &Thread,
-        f: &mut Option<impl FnOnce(&mut HashMap<Id, T>)-> I>,
-    ) -> Option<I> {
-        let mut lock = self.0.get(thread)?.spans.lock();
-        let mut shard = lock.borrow_mut();
-        let mut f = f.take()?;
-        Some(f(&mut *shard))
+    /// Walks every shard and yields `(Id, Ref<'_, T>)` for each live span,
+    /// taking the registry's read lock and each shard's lock as it goes.
+    /// `Slot::Stolen` markers are skipped, so a span is visited exactly
+    /// once no matter which shard currently holds it.
+    pub fn iter(&self) -> impl Iterator<Item = (Id, Ref<'_, T>)> + '_ {
+        let ids = self.shards.read().0.iter()
+            .flat_map(|(thread, shard)| shard.present_ids(thread.id))
+            .collect::<Vec<_>>();
+
+        ids.into_iter().filter_map(move |id| {
+            let span = self.ref_at(SlotKey::from_id(&id))?;
+            Some((id, span))
+        })
     }
 
-    fn new_shard_for(&mut self, thread: Thread) -> &mut Self {
-        self.0.insert(thread, Shard::new());
-        self
+    /// Like `iter`, but takes `&mut self` so no shard needs locking: the
+    /// borrow checker already guarantees nothing else can be touching the
+    /// registry concurrently.
+    pub fn unique_iter(&mut self) -> impl Iterator<Item = (Id, &mut T)> + '_ {
+        self.shards.get_mut().0.iter_mut().flat_map(|(thread, shard)| {
+            let shard_id = thread.id;
+            // `&mut self` means the borrow checker has already ruled out any
+            // other live borrow of the registry, including any `Ref` that
+            // might otherwise be holding a clone of this shard's `Arc` (see
+            // `Registry::ref_at`) --- so this `Arc` is always uniquely held
+            // by the time we get here.
+            let shard = Arc::get_mut(shard).expect("&mut Registry guarantees no outstanding Refs");
+            let storage = shard.storage.get_mut().get_mut();
+            storage.pages.iter_mut().enumerate().flat_map(move |(page, slots)| {
+                slots.iter_mut().enumerate().filter_map(move |(offset, slot)| {
+                    let generation = slot.generation;
+                    // A released slot keeps its cleared value for reuse, but
+                    // its refcount is zero: it's not a live span.
+                    let live = slot.refs > 0;
+                    match &mut slot.value {
+                        Some(Slot::Present(span)) if live => Some((
+                            SlotKey { shard: shard_id, page, offset, generation }.to_id(),
+                            span,
+                        )),
+                        _ => None,
+                    }
+                })
+            })
+        })
+    }
+}
+
+impl<T> Default for Registry<T> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl<T> Shard<T> {
     fn new() -> Self {
         Self {
-            spans: ReentrantMutex::new(RefCell::new(HashMap::new()))
+            storage: ReentrantMutex::new(RefCell::new(ShardStorage::new()))
         }
     }
 
-    fn span<'a>(&'a self, id: &Id) -> Option<ReentrantMutexGuard<'a, &mut T>> {
-        let guard = self.spans.lock();
-        ReentrantMutexGuard::try_map(
-            guard,
-            move |spans| spans.get(id).and_then(Slot::get_mut)
-        ).ok()
+    fn with_storage<I>(&self, f: impl FnOnce(&mut ShardStorage<T>) -> I) -> I {
+        let lock = self.storage.lock();
+        let mut storage = lock.borrow_mut();
+        f(&mut storage)
     }
 
-    fn try_steal(&self, id: &Id) -> Option<Slot<T>> {
-        let mut lock = self.spans.lock();
-        let slot = self.spans.get_mut(id)?;
-        mem::replace(slot, Slot::Stolen(Thread::current()))
+    /// Locks this shard's storage and hands back the raw guard, rather than
+    /// running a closure against it. Used by `Registry::localize`, which
+    /// needs to hold two shards' locks at once across several steps of a
+    /// steal, so a single `with_storage` closure won't do.
+    fn lock(&self) -> ReentrantMutexGuard<'_, RefCell<ShardStorage<T>>> {
+        self.storage.lock()
     }
-}
 
-impl Thread {
-    fn current() -> Self {
-        static NEXT: AtomicUsize = AtomicUsize::new(0);
-        thread_local! {
-            static MY_ID: Cell<Option<usize>> = Cell::new(None);
+    /// Reads whether `key` currently points at a present span or a
+    /// forwarding marker, without taking or moving anything. The `Present`
+    /// variant's payload is discarded (`()`) since callers only care which
+    /// case it is; `present`/`with_present` do the actual value access.
+    fn peek(&self, key: &SlotKey) -> Option<Slot<()>> {
+        let lock = self.storage.lock();
+        let storage = lock.borrow();
+        let slot = storage.slot(key.page, key.offset)?;
+        if slot.generation != key.generation {
+            return None;
         }
-        MY_ID.with(|my_id| if let Some(id) = my_id.get() {
-            Thread {
-                id
+        match slot.value.as_ref()? {
+            Slot::Present(_) => Some(Slot::Present(())),
+            Slot::Stolen(next) => Some(Slot::Stolen(*next)),
+        }
+    }
+
+    /// Runs `f` against the span at `key` if it's still `Present` there.
+    /// Returns `None` if `key`'s generation is stale (the span is genuinely
+    /// gone). If a steal has since turned the slot into a `Stolen` marker
+    /// --- without bumping the generation --- `f` is handed back unused
+    /// inside `Access::Stolen` so the caller can retry it once the span has
+    /// been chased down again, instead of either dropping `f` or silently
+    /// reporting the span missing.
+    fn with_present<F, I>(&self, key: &SlotKey, f: F) -> Option<Access<F, I>>
+    where
+        F: FnOnce(&mut T) -> I,
+    {
+        let lock = self.storage.lock();
+        let mut storage = lock.borrow_mut();
+        let slot = storage.slot_mut(key.page, key.offset)?;
+        if slot.generation != key.generation {
+            return None;
+        }
+        match slot.value.as_mut()? {
+            Slot::Present(span) => Some(Access::Found(f(span))),
+            Slot::Stolen(_) => Some(Access::Stolen(f)),
+        }
+    }
+
+    fn present<'a>(&'a self, key: &SlotKey) -> Option<MappedReentrantMutexGuard<'a, T>> {
+        let guard = self.storage.lock();
+        ReentrantMutexGuard::try_map(guard, |storage| {
+            // Safety: holding the shard's `ReentrantMutex` guarantees no
+            // other thread is touching this shard's `RefCell` right now,
+            // and a reentrant call on this thread only ever sees the
+            // shared `&T` we were handed, never a second mutable borrow.
+            let storage = unsafe { &mut *storage.as_ptr() };
+            let slot = storage.slot_mut(key.page, key.offset)?;
+            if slot.generation != key.generation {
+                return None;
             }
-        } else {
-            let id = NEXT.fetch_add(1, Ordering::SeqCst);
-            my_id.set(Some(id));
-            Thread {
-                id
+            match slot.value.as_mut()? {
+                Slot::Present(span) => Some(span),
+                Slot::Stolen(_) => None,
             }
-        })
+        }).ok()
+    }
+
+    /// Increments the refcount of the span at `key`. Returns `None` if
+    /// `key`'s generation is stale (the span is genuinely gone), or
+    /// `Some(Touch::Stolen(next))` if a steal has since forwarded this slot
+    /// elsewhere --- without bumping the generation, so this has to be
+    /// detected from the slot's contents, not just its generation --- in
+    /// which case the caller should retry at `next`.
+    fn clone_ref(&self, key: &SlotKey) -> Option<Touch<()>> {
+        let lock = self.storage.lock();
+        let mut storage = lock.borrow_mut();
+        let slot = storage.slot_mut(key.page, key.offset)?;
+        if slot.generation != key.generation {
+            return None;
+        }
+        match slot.value.as_ref()? {
+            Slot::Present(_) => {
+                slot.refs += 1;
+                Some(Touch::Done(()))
+            }
+            Slot::Stolen(next) => Some(Touch::Stolen(*next)),
+        }
     }
+
+    fn drop_ref(&self, key: &SlotKey) -> Option<Touch<DropRef>>
+    where
+        T: Clear,
+    {
+        let lock = self.storage.lock();
+        let mut storage = lock.borrow_mut();
+        storage.drop_ref(key)
+    }
+
+    /// Frees a `Stolen` tombstone at `key` once whatever it forwarded to has
+    /// itself been fully removed. See `ShardStorage::free_stolen`.
+    fn free_stolen(&self, key: &SlotKey) -> Option<SlotKey> {
+        let lock = self.storage.lock();
+        let mut storage = lock.borrow_mut();
+        storage.free_stolen(key)
+    }
+
+    /// Test-only; see `ShardStorage::remove`.
+    #[cfg(test)]
+    fn remove(&self, key: &SlotKey) -> Option<(Slot<T>, Option<SlotKey>)> {
+        let lock = self.storage.lock();
+        let mut storage = lock.borrow_mut();
+        storage.remove(key)
+    }
+
+    /// Collects the `Id`s of every slot in this shard that's currently
+    /// `Present`, skipping empty slots, `Stolen` forwarding markers, and
+    /// released slots that only hold a cleared value awaiting reuse.
+    fn present_ids(&self, shard_id: usize) -> Vec<Id> {
+        let lock = self.storage.lock();
+        let storage = lock.borrow();
+        let mut ids = Vec::new();
+        for (page, slots) in storage.pages.iter().enumerate() {
+            for (offset, slot) in slots.iter().enumerate() {
+                if matches!(slot.value, Some(Slot::Present(_))) && slot.refs > 0 {
+                    ids.push(SlotKey { shard: shard_id, page, offset, generation: slot.generation }.to_id());
+                }
+            }
+        }
+        ids
+    }
+}
+
+/// Hands out dense, reusable thread ids.
+///
+/// Ids are allocated from a min-heap of previously-freed ids, falling back
+/// to a monotonic counter once the heap is empty. Always handing back the
+/// *smallest* free id (rather than, say, a stack of recently-freed ones)
+/// keeps the id space dense even under workloads that continually spawn and
+/// join short-lived threads, which matters now that a thread's id doubles
+/// as the shard index packed into every `SlotKey`.
+struct ThreadIdAllocator {
+    next: AtomicUsize,
+    free: Mutex<BinaryHeap<Reverse<usize>>>,
 }
 
-impl<T> Slot<T> {
-    fn get_mut(&mut self) -> Option<&mut T> {
-        match self {
-            Slot::Present(ref mut span) => Some(span),
-            _ => None,
+impl ThreadIdAllocator {
+    const fn new() -> Self {
+        Self {
+            next: AtomicUsize::new(0),
+            free: Mutex::new(BinaryHeap::new()),
+        }
+    }
+
+    fn acquire(&self) -> usize {
+        if let Some(Reverse(id)) = self.free.lock().pop() {
+            return id;
+        }
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn release(&self, id: usize) {
+        self.free.lock().push(Reverse(id));
+    }
+}
+
+static THREAD_IDS: ThreadIdAllocator = ThreadIdAllocator::new();
+
+struct ThreadLocal {
+    thread: Thread,
+}
+
+impl ThreadLocal {
+    fn new() -> Self {
+        Self {
+            thread: Thread { id: THREAD_IDS.acquire() },
+        }
+    }
+}
+
+impl Drop for ThreadLocal {
+    fn drop(&mut self) {
+        // Note that we don't (and don't need to) touch any `Registry` here.
+        // A `Shards` entry is keyed by thread id, and every slot within it
+        // is addressed explicitly by `SlotKey` rather than by "whichever
+        // thread currently owns this shard" --- so a dying thread's spans
+        // remain exactly as reachable as they were before, and the next
+        // thread handed this id simply grows the same shard further rather
+        // than inheriting or clobbering anything.
+        THREAD_IDS.release(self.thread.id);
+    }
+}
+
+thread_local! {
+    static THREAD: ThreadLocal = ThreadLocal::new();
+}
+
+impl Thread {
+    fn current() -> Self {
+        THREAD.with(|local| local.thread)
+    }
+}
+
+#[cfg(test)]
+impl<T> Registry<T> {
+    fn shard_count(&self) -> usize {
+        self.shards.read().0.len()
+    }
+
+    /// Test-only hard removal, bypassing the refcount entirely.
+    fn remove_for_test(&self, id: &Id) -> Option<T> {
+        let key = SlotKey::from_id(id);
+        let shards = self.shards.read();
+        let shard = shards.0.get(&Thread { id: key.shard })?;
+        match shard.remove(&key)?.0 {
+            Slot::Present(span) => Some(span),
+            Slot::Stolen(_) => None,
         }
     }
 }
@@ -175,17 +1019,259 @@ mod tests {
     #[test]
     fn basically_works() {
         let registry: Registry<usize> = Registry::new();
-        registry
-            .insert(Id::from_u64(1), 1)
-            .insert(Id::from_u64(2), 2);
+        let one = registry.insert(1);
+        let two = registry.insert(2);
+
+        assert_eq!(registry.with_span(&one, |&mut s| s), Some(1));
+        assert_eq!(registry.with_span(&two, |&mut s| s), Some(2));
+
+        let three = registry.insert(3);
+
+        assert_eq!(registry.with_span(&one, |&mut s| s), Some(1));
+        assert_eq!(registry.with_span(&two, |&mut s| s), Some(2));
+        assert_eq!(registry.with_span(&three, |&mut s| s), Some(3));
+    }
+
+    #[test]
+    fn steals_spans_across_threads() {
+        let registry: Registry<usize> = Registry::new();
+        // Take this thread's id up front, so the thread spawned below can't
+        // be handed the same (smallest free) id and share our shard.
+        Thread::current();
+        let id = std::sync::Mutex::new(None);
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                *id.lock().unwrap() = Some(registry.insert(42));
+            });
+        });
+        let id = id.into_inner().unwrap().unwrap();
+
+        // The span was created on another (now-dead) thread, so reading it
+        // here must steal it into this thread's shard.
+        assert_eq!(registry.with_span(&id, |&mut s| s), Some(42));
+        // ... and it should now resolve locally without stealing again.
+        assert_eq!(registry.with_span(&id, |&mut s| s), Some(42));
+    }
+
+    #[test]
+    fn steals_twice_chains_through_both_markers() {
+        let registry: Registry<usize> = Registry::new();
+        // Hold this thread's id for the whole test, so no thread spawned
+        // below can be handed it.
+        Thread::current();
+        let id = std::sync::Mutex::new(None);
+
+        // Each of the two lookups runs on its own thread, and every thread
+        // stays alive (holding its id) while it spawns the next, so all of
+        // them have distinct shards. Every lookup therefore steals the span
+        // again, leaving a chain of `Stolen` markers two hops deep before
+        // it's finally read back from here.
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let span = registry.insert(7);
+                std::thread::scope(|scope| {
+                    scope.spawn(|| {
+                        assert_eq!(registry.with_span(&span, |&mut s| s), Some(7));
+                        std::thread::scope(|scope| {
+                            scope.spawn(|| {
+                                assert_eq!(registry.with_span(&span, |&mut s| s), Some(7));
+                            });
+                        });
+                    });
+                });
+                *id.lock().unwrap() = Some(span);
+            });
+        });
+        let id = id.into_inner().unwrap().unwrap();
+
+        // `id`'s bits never change --- resolving it here has to walk past
+        // both leftover markers to find where the span actually lives now.
+        assert_eq!(registry.with_span(&id, |&mut s| s), Some(7));
+    }
+
+    #[test]
+    fn concurrent_steals_of_the_same_span_all_find_it() {
+        let registry: Registry<usize> = Registry::new();
+        let id = std::sync::Mutex::new(None);
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                *id.lock().unwrap() = Some(registry.insert(9));
+            });
+        });
+        let id = id.into_inner().unwrap().unwrap();
+
+        // Several threads race to steal the same span out of its (now-dead)
+        // origin shard at once. The donor shard's lock is held for the
+        // whole steal, so none of them should ever observe the span as
+        // missing, even mid-move.
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    assert_eq!(registry.with_span(&id, |&mut s| s), Some(9));
+                });
+            }
+        });
+    }
+
+    #[test]
+    fn thread_ids_and_shards_are_reclaimed() {
+        let registry: Registry<usize> = Registry::new();
 
-        assert_eq!(registry.with_span(&Id::from_u64(1), |&mut s| s), Some(1));
-        assert_eq!(registry.with_span(&Id::from_u64(2), |&mut s| s), Some(2));
+        for i in 0..64usize {
+            std::thread::scope(|scope| {
+                scope.spawn(|| {
+                    registry.insert(i);
+                });
+            });
+        }
+
+        // Each iteration above spawns and joins a single thread before
+        // moving on to the next, so at most a handful of thread ids should
+        // ever be outstanding at once --- other tests in this binary share
+        // the same global id allocator and may transiently hold a few ids
+        // of their own, so we only assert the shard count stays small
+        // rather than pinning it to an exact number.
+        assert!(
+            registry.shard_count() <= 4,
+            "shard count grew unbounded: {}",
+            registry.shard_count()
+        );
+    }
+
+    #[test]
+    fn stale_id_after_reuse_resolves_to_none() {
+        let registry: Registry<usize> = Registry::new();
+        let id = registry.insert(1);
+
+        assert_eq!(registry.with_span(&id, |&mut s| s), Some(1));
+
+        registry.remove_for_test(&id);
+        assert_eq!(registry.with_span(&id, |&mut s| s), None);
+
+        // The slot `id` pointed at gets reused by the very next insert
+        // (the free-list is LIFO and nothing else has touched this shard)...
+        let reused = registry.insert(2);
+        assert_eq!(registry.with_span(&reused, |&mut s| s), Some(2));
+        // ... but the old, now-stale `Id` must not resolve to it.
+        assert_eq!(registry.with_span(&id, |&mut s| s), None);
+    }
+
+    impl Clear for usize {
+        fn clear(&mut self) {
+            *self = 0;
+        }
+    }
+
+    #[derive(Default)]
+    struct Buffer {
+        data: Vec<u32>,
+    }
+
+    impl Clear for Buffer {
+        fn clear(&mut self) {
+            self.data.clear();
+        }
+    }
+
+    #[test]
+    fn clone_keeps_span_alive_until_every_handle_drops() {
+        let registry: Registry<usize> = Registry::new();
+        let id = registry.insert(1);
+        let clone = registry.clone_span(&id).expect("clone_span");
+
+        assert!(!registry.drop_span(&id), "span still referenced by `clone`");
+        assert_eq!(registry.with_span(&clone, |&mut s| s), Some(1));
+
+        assert!(registry.drop_span(&clone), "last handle should release the span");
+        assert_eq!(registry.with_span(&id, |&mut s| s), None);
+    }
+
+    #[test]
+    fn drops_out_of_order() {
+        let registry: Registry<usize> = Registry::new();
+        let id = registry.insert(1);
+        let first = registry.clone_span(&id).unwrap();
+        let second = registry.clone_span(&id).unwrap();
+
+        // Three handles now reference `id`; drop them in an order other
+        // than clone order and confirm the span only goes away on the last.
+        assert!(!registry.drop_span(&second));
+        assert!(!registry.drop_span(&id));
+        assert!(registry.drop_span(&first));
+        assert_eq!(registry.with_span(&id, |&mut s| s), None);
+    }
+
+    #[test]
+    fn capacity_is_retained_across_reuse() {
+        let registry: Registry<Buffer> = Registry::new();
+
+        let id = registry.insert_with(|buf| buf.data.extend(0..256));
+        let capacity = registry.with_span(&id, |buf| buf.data.capacity()).unwrap();
+        assert!(registry.drop_span(&id));
+
+        // The freed slot's `Buffer` was cleared in place, not dropped, so
+        // reusing it should start from the same allocation rather than
+        // growing one from scratch.
+        let reused = registry.insert_with(|buf| buf.data.push(1));
+        let reused_capacity = registry
+            .with_span(&reused, |buf| buf.data.capacity())
+            .unwrap();
+
+        assert_eq!(reused_capacity, capacity);
+        assert_eq!(registry.with_span(&reused, |buf| buf.data.clone()), Some(vec![1]));
+    }
+
+    #[test]
+    fn iter_visits_each_span_once_and_skips_stolen() {
+        let registry: Registry<usize> = Registry::new();
+        // Take this thread's id up front, so the thread spawned below gets a
+        // different shard and reading its span here really is a steal.
+        Thread::current();
+        let remote_id = std::sync::Mutex::new(None);
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                *remote_id.lock().unwrap() = Some(registry.insert(1));
+            });
+        });
+        let remote_id = remote_id.into_inner().unwrap().unwrap();
+        registry.insert(2);
+
+        // Steal `remote_id` into this thread's shard, leaving a `Stolen`
+        // marker behind on its original (now-dead) shard. `iter` must still
+        // report exactly one entry for it, not zero (missed) or two
+        // (double-counted via the leftover marker).
+        assert_eq!(registry.with_span(&remote_id, |&mut s| s), Some(1));
+
+        let mut seen: Vec<usize> = registry.iter().map(|(_, span)| *span).collect();
+        seen.sort();
+        assert_eq!(seen, vec![1, 2]);
+    }
+
+    #[test]
+    fn unique_iter_sees_mutations() {
+        let mut registry: Registry<usize> = Registry::new();
+        let one = registry.insert(1);
+        let two = registry.insert(2);
+
+        for (_, span) in registry.unique_iter() {
+            *span *= 10;
+        }
+
+        assert_eq!(registry.with_span(&one, |&mut s| s), Some(10));
+        assert_eq!(registry.with_span(&two, |&mut s| s), Some(20));
+    }
+
+    #[test]
+    fn try_with_span_reports_missing_spans() {
+        let registry: Registry<usize> = Registry::new();
+        let id = registry.insert(1);
 
-        registry.insert(Id::from_u64(3), 3);
+        assert_eq!(registry.try_with_span(&id, |&mut s| s), Ok(1));
 
-        assert_eq!(registry.with_span(&Id::from_u64(1), |&mut s| s), Some(1));
-        assert_eq!(registry.with_span(&Id::from_u64(2), |&mut s| s), Some(2));
-        assert_eq!(registry.with_span(&Id::from_u64(3), |&mut s| s), Some(3));
+        registry.remove_for_test(&id);
+        assert_eq!(registry.try_with_span(&id, |&mut s| s), Err(SpanNotFound));
     }
 }